@@ -1,16 +1,82 @@
 extern crate url;
 extern crate hyper;
 extern crate xml;
+extern crate flate2;
+extern crate rand;
 
-use self::url::{Url, ParseError};
+use self::url::{Url, UrlParser, ParseError};
+use self::url::form_urlencoded;
 use self::hyper::server::response::Response;
 use self::hyper::server::request::Request;
+use self::hyper::client::response::Response as ClientResponse;
+use self::hyper::client::RedirectPolicy;
 use self::hyper::status::StatusCode;
-use self::hyper::header::Location;
+use self::hyper::header::{Location, ContentEncoding, Encoding};
 use self::hyper::Client;
+use self::hyper::server::Handler;
 use self::hyper::error::Error as HyperError;
 use self::hyper::uri::RequestUri;
 use self::xml::reader::{EventReader, XmlEvent, Error as XmlError};
+use self::flate2::read::{GzDecoder, DeflateDecoder};
+use self::rand::Rng;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Error as IoError};
+use std::str;
+use std::sync::Mutex;
+
+/// The name of the cookie `CasGuard` uses to track an established session
+const CAS_SESSION_COOKIE: &'static str = "cas_session";
+
+/// How many redirects a single validation fetch will follow before giving up
+const MAX_REDIRECTS: u32 = 10;
+
+/// Mint a fresh, opaque session id for `CasGuard` to hand out as a cookie,
+/// unrelated to the single-use CAS service ticket.
+fn new_session_id() -> String {
+    rand::thread_rng().gen_ascii_chars().take(32).collect()
+}
+
+/// Pull the `<samlp:SessionIndex>` (the original service ticket) out of a SAML
+/// `<samlp:LogoutRequest>` document.  Returns `VerifyError::NoSessionIndex`
+/// when the element is absent.
+fn parse_session_index(xml: &str) -> Result<Name, VerifyError> {
+    let parser = EventReader::new(xml.as_bytes());
+    let mut text = String::new();
+    let mut session_index: Option<String> = None;
+    for e in parser {
+        match try!(e) {
+            XmlEvent::StartElement { .. } => {
+                text.clear();
+            }
+            XmlEvent::Characters(s) => {
+                text.push_str(&s);
+            }
+            XmlEvent::EndElement { name } => {
+                if name.local_name == "SessionIndex" {
+                    session_index = Some(text.trim().to_owned());
+                }
+                text.clear();
+            }
+            _ => {}
+        }
+    }
+    session_index.ok_or(VerifyError::NoSessionIndex)
+}
+
+/// Resolve a `Location` header against the url it was returned from.  When the
+/// redirect leaves the original host the query string — which carries the CAS
+/// ticket and service — is dropped so it is never leaked to a third party.
+fn redirect_target(base: &Url, location: &str) -> Result<Url, VerifyError> {
+    let mut next = try!(UrlParser::new().base_url(base).parse(location));
+    if next.domain() != base.domain() {
+        let serialized = next.serialize();
+        let bare = serialized.split('?').next().unwrap_or("").to_owned();
+        next = try!(Url::parse(&bare));
+    }
+    Ok(next)
+}
 
 /// The username returned by `verify_ticket` on success
 pub type Name = String;
@@ -18,7 +84,6 @@ pub type Name = String;
 pub type TicketError = String;
 
 /// The details of a CAS server.  All URLs are the full urls
-#[derive(Debug)]
 pub struct CasClient {
     /// Login url (such as https://login.case.edu/cas/login)
     login_url: Url,
@@ -30,31 +95,183 @@ pub struct CasClient {
     /// The URL of your service, which is used in the login sequence and
     /// so the login server knows where to redirect you back to
     service_url: Url,
+    /// An optional override for `service_url`, used when a single client serves
+    /// more than one virtual host.  When set it is the service presented at
+    /// login *and* validation, so the request-based helpers
+    /// (`verify_from_request`, `CasGuard`) keep the two in sync.
+    service_override: Option<String>,
+    /// The callback url CAS posts the proxy-granting ticket to.  When set, it
+    /// is sent as `pgtUrl` during validation so the server issues a PGT.
+    pgt_callback_url: Option<Url>,
+    /// The proxyValidate url (such as https://login.case.edu/cas/proxyValidate)
+    proxy_validate_url: Option<Url>,
+    /// The proxy url (such as https://login.case.edu/cas/proxy), used to trade
+    /// a PGT for a proxy ticket against a downstream service
+    proxy_url: Option<Url>,
+    /// Where proxy-granting tickets deposited at `pgt_callback_url` are kept
+    pgt_store: Box<PgtStore>,
+    /// Maps service tickets to local sessions so Single Logout can drop them
+    session_store: Box<SessionStore>,
+    /// A single pooled hyper client, built once so HTTPS connections to the
+    /// validation endpoint are reused across `verify_ticket` calls rather than
+    /// renegotiating TLS every time.
+    client: Client,
+}
+
+impl fmt::Debug for CasClient {
+    // `hyper::Client` is not `Debug`, so list the rest of the fields by hand.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CasClient")
+            .field("login_url", &self.login_url)
+            .field("logout_url", &self.logout_url)
+            .field("verify_url", &self.verify_url)
+            .field("service_url", &self.service_url)
+            .field("service_override", &self.service_override)
+            .field("pgt_callback_url", &self.pgt_callback_url)
+            .field("proxy_validate_url", &self.proxy_validate_url)
+            .field("proxy_url", &self.proxy_url)
+            .field("pgt_store", &self.pgt_store)
+            .field("session_store", &self.session_store)
+            .finish()
+    }
+}
+
+/// The authenticated user together with any attributes the CAS server
+/// released in the `<cas:attributes>` block of a 3.0 validation response.
+/// An attribute key can appear more than once (e.g. multiple `memberOf`),
+/// so values are collected into a `Vec`.
+#[derive(Debug)]
+pub struct AuthSuccess {
+    /// The username, taken from `<cas:user>`
+    pub user: Name,
+    /// The released attributes, keyed by element local name
+    pub attributes: HashMap<String, Vec<String>>,
+    /// The proxy-granting-ticket IOU from `<cas:proxyGrantingTicket>`, present
+    /// only when a `pgt_callback_url` was registered and the CAS server issued
+    /// a PGT.  Correlate it with the real PGT deposited at the callback via the
+    /// client's `PgtStore`.
+    pub pgt_iou: Option<String>,
+    /// The chain of proxies from `<cas:proxies>`, populated by `proxy_validate`
+    /// (empty for a direct `serviceValidate`).
+    pub proxies: Vec<String>,
+}
+
+/// A pluggable store mapping proxy-granting-ticket IOUs to the actual PGT the
+/// CAS server deposits at the `pgt_callback_url`.  This mirrors the
+/// issued-token bookkeeping of a bearer-token flow: the validation response
+/// carries only the IOU, while the real ticket arrives out of band and must be
+/// looked up later.
+pub trait PgtStore: fmt::Debug + Send + Sync {
+    /// Record the PGT the server deposited under its IOU
+    fn store(&self, pgt_iou: &str, pgt_id: &str);
+    /// Look up the PGT for a previously seen IOU
+    fn retrieve(&self, pgt_iou: &str) -> Option<String>;
+}
+
+/// The default in-memory `PgtStore`, backed by a `Mutex<HashMap<..>>`
+#[derive(Debug)]
+pub struct MemoryPgtStore {
+    tickets: Mutex<HashMap<String, String>>,
+}
+
+impl MemoryPgtStore {
+    pub fn new() -> MemoryPgtStore {
+        MemoryPgtStore { tickets: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl PgtStore for MemoryPgtStore {
+    fn store(&self, pgt_iou: &str, pgt_id: &str) {
+        self.tickets.lock().unwrap().insert(pgt_iou.to_owned(), pgt_id.to_owned());
+    }
+
+    fn retrieve(&self, pgt_iou: &str) -> Option<String> {
+        self.tickets.lock().unwrap().get(pgt_iou).cloned()
+    }
+}
+
+/// A pluggable store mapping CAS service tickets to whatever local session id
+/// the application established for them.  Single Logout hands back a service
+/// ticket (the SAML `SessionIndex`); this lets the crate find the session to
+/// drop.  Like `PgtStore`, the default lives behind a `Mutex<HashMap<..>>`.
+pub trait SessionStore: fmt::Debug + Send + Sync {
+    /// Associate a service ticket with a local session id
+    fn store(&self, ticket: &str, session_id: &str);
+    /// Look up the session id for a service ticket
+    fn retrieve(&self, ticket: &str) -> Option<String>;
+    /// Forget a service ticket, returning the session id it was mapped to
+    fn remove(&self, ticket: &str) -> Option<String>;
+}
+
+/// The default in-memory `SessionStore`, backed by a `Mutex<HashMap<..>>`
+#[derive(Debug)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, String>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> MemorySessionStore {
+        MemorySessionStore { sessions: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn store(&self, ticket: &str, session_id: &str) {
+        self.sessions.lock().unwrap().insert(ticket.to_owned(), session_id.to_owned());
+    }
+
+    fn retrieve(&self, ticket: &str) -> Option<String> {
+        self.sessions.lock().unwrap().get(ticket).cloned()
+    }
+
+    fn remove(&self, ticket: &str) -> Option<String> {
+        self.sessions.lock().unwrap().remove(ticket)
+    }
+}
+
+/// The optional CAS login modifiers passed to `get_login_url_with` /
+/// `login_redirect_with`.  All default to off / the client's own service url.
+#[derive(Debug, Default)]
+pub struct LoginOptions {
+    /// Send `gateway=true`: attempt SSO silently and redirect straight back
+    /// without prompting if the user has no existing session
+    pub gateway: bool,
+    /// Send `renew=true`: force re-authentication, ignoring any existing SSO
+    pub renew: bool,
+    /// Override the `service` url for this call (for a client that serves more
+    /// than one virtual host or return path).  The same value must be handed
+    /// to `verify_ticket_with` at validation time.
+    pub service: Option<String>,
 }
 
 /// The response from the server from `verify_ticket`
 #[derive(Debug)]
 pub enum ServiceResponse {
     /// Returned on successful login
-    Success(Name),
+    Success(AuthSuccess),
     /// Returned on unsuccessful login
     Failure(TicketError),
 }
 
-#[derive(Debug)]
-enum XmlMatchStatus {
-    None,
-    ExpectSuccess,
-}
-
 /// Errors that can happen when verifying.  Xml is unlikely.
 #[derive(Debug)]
 pub enum VerifyError {
     Hyper(HyperError),
     Xml(XmlError),
     Url(ParseError),
+    Io(IoError),
     UnsupportedUriType,
     NoTicketFound,
+    /// The validation fetch exceeded `MAX_REDIRECTS` redirects
+    TooManyRedirects,
+    /// A back-channel `logoutRequest` was received but carried no
+    /// `<samlp:SessionIndex>`
+    NoSessionIndex,
+    /// A proxy operation was requested but the relevant url was never
+    /// configured with `set_proxy`
+    ProxyNotConfigured,
+    /// The `/proxy` response contained no `<cas:proxyTicket>`
+    NoProxyTicket(TicketError),
 }
 
 impl From<HyperError> for VerifyError {
@@ -72,6 +289,11 @@ impl From<ParseError> for VerifyError {
         VerifyError::Url(err)
     }
 }
+impl From<IoError> for VerifyError {
+    fn from(err: IoError) -> VerifyError {
+        VerifyError::Io(err)
+    }
+}
 
 impl CasClient {
     /// Construct a new CasClient. The for each url except service_url, the
@@ -82,19 +304,120 @@ impl CasClient {
                verify_path: &str,
                service_url: &str)
                -> Result<CasClient, ParseError> {
+        CasClient::with_client(base_url,
+                               login_path,
+                               logout_path,
+                               verify_path,
+                               service_url,
+                               Client::new())
+    }
+
+    /// Like `new`, but uses a caller-supplied `hyper::Client`.  Campus CAS
+    /// servers often use a private PKI, so pass a `Client` built around a
+    /// custom TLS connector with the internal CA pinned.  The client's
+    /// connection pool is shared by every verification this `CasClient` makes.
+    pub fn with_client(base_url: &str,
+                       login_path: &str,
+                       logout_path: &str,
+                       verify_path: &str,
+                       service_url: &str,
+                       mut client: Client)
+                       -> Result<CasClient, ParseError> {
+        // We follow redirects ourselves (with a hop cap and host-change param
+        // scrubbing), so disable hyper's automatic redirect handling.
+        client.set_redirect_policy(RedirectPolicy::FollowNone);
         Ok(CasClient {
             login_url: try!(Url::parse(&format!("{}{}", base_url, login_path))),
             logout_url: try!(Url::parse(&format!("{}{}", base_url, logout_path))),
             verify_url: try!(Url::parse(&format!("{}{}", base_url, verify_path))),
             service_url: try!(Url::parse(service_url)),
+            service_override: None,
+            pgt_callback_url: None,
+            proxy_validate_url: None,
+            proxy_url: None,
+            pgt_store: Box::new(MemoryPgtStore::new()),
+            session_store: Box::new(MemorySessionStore::new()),
+            client: client,
         })
     }
 
+    /// Enable CAS proxy authentication.  `callback_url` is the full url on
+    /// *your* service where the CAS server deposits proxy-granting tickets
+    /// (sent as `pgtUrl` during validation) — it usually lives on a different
+    /// host than the CAS server, so it is taken verbatim.  `proxy_validate_path`
+    /// (backing `proxy_validate`) and `proxy_path` (backing `get_proxy_ticket`)
+    /// are concatenated onto `base_url` exactly like the urls given to `new`.
+    pub fn set_proxy(&mut self,
+                     base_url: &str,
+                     callback_url: &str,
+                     proxy_validate_path: &str,
+                     proxy_path: &str)
+                     -> Result<(), ParseError> {
+        self.pgt_callback_url = Some(try!(Url::parse(callback_url)));
+        self.proxy_validate_url =
+            Some(try!(Url::parse(&format!("{}{}", base_url, proxy_validate_path))));
+        self.proxy_url = Some(try!(Url::parse(&format!("{}{}", base_url, proxy_path))));
+        Ok(())
+    }
+
+    /// Replace the proxy-granting-ticket store (defaults to `MemoryPgtStore`)
+    pub fn set_pgt_store(&mut self, store: Box<PgtStore>) {
+        self.pgt_store = store;
+    }
+
+    /// Look up the real proxy-granting ticket the CAS server deposited at the
+    /// callback for a given IOU (the `AuthSuccess.pgt_iou`).  Feed the result
+    /// to `get_proxy_ticket`.  Returns `None` until the callback has fired.
+    pub fn proxy_granting_ticket(&self, pgt_iou: &str) -> Option<String> {
+        self.pgt_store.retrieve(pgt_iou)
+    }
+
+    /// Replace the service-ticket/session store (defaults to
+    /// `MemorySessionStore`)
+    pub fn set_session_store(&mut self, store: Box<SessionStore>) {
+        self.session_store = store;
+    }
+
+    /// Override the `service` value used at both login and validation, for a
+    /// client that serves a different virtual host / return path than the
+    /// `service_url` given to `new`.  This is the persisted counterpart to the
+    /// per-call `LoginOptions::service`, and the request-based helpers
+    /// (`verify_from_request`, `CasGuard`) honour it automatically.
+    pub fn set_service(&mut self, service: &str) {
+        self.service_override = Some(service.to_owned());
+    }
+
+    /// The persisted service override, if one was set with `set_service`
+    fn service_override(&self) -> Option<&str> {
+        self.service_override.as_ref().map(|s| &s[..])
+    }
+
     /// Get the URL to redirect to for login.  Use this if you are not using
     /// Hyper as your web server
     pub fn get_login_url(&self) -> String {
+        self.get_login_url_with(&LoginOptions::default())
+    }
+
+    /// Like `get_login_url`, but honours the CAS login modifiers in `opts`:
+    /// `gateway=true` attempts SSO silently and redirects straight back if the
+    /// user has no session, `renew=true` forces re-authentication, and a
+    /// `service` override lets a single client serve several virtual hosts.
+    /// Whatever `service` ends up being used here must be passed back to
+    /// `verify_ticket_with`, since CAS requires the two to match exactly.
+    pub fn get_login_url_with(&self, opts: &LoginOptions) -> String {
         let mut url = self.login_url.clone();
-        let param = vec![("service", self.service_url.serialize())];
+        // Per-call override wins, then the persisted `set_service` override,
+        // then the client's own service url.
+        let service = opts.service.clone()
+            .or_else(|| self.service_override.clone())
+            .unwrap_or_else(|| self.service_url.serialize());
+        let mut param = vec![("service", service)];
+        if opts.gateway {
+            param.push(("gateway", "true".to_owned()));
+        }
+        if opts.renew {
+            param.push(("renew", "true".to_owned()));
+        }
         url.set_query_from_pairs(param);
         url.serialize()
     }
@@ -102,9 +425,15 @@ impl CasClient {
     /// Consumes a hyper::server::response::Response to return a 302 redirect
     /// to the CAS login url.  Use this if you're using Hyper as you web
     /// server
-    pub fn login_redirect(&self, mut res: Response) {
+    pub fn login_redirect(&self, res: Response) {
+        self.login_redirect_with(res, &LoginOptions::default())
+    }
+
+    /// Like `login_redirect`, but applies the `gateway`/`renew`/`service`
+    /// modifiers from `opts`
+    pub fn login_redirect_with(&self, mut res: Response, opts: &LoginOptions) {
         *res.status_mut() = StatusCode::Found;
-        res.headers_mut().set::<Location>(Location(self.get_login_url()));
+        res.headers_mut().set::<Location>(Location(self.get_login_url_with(opts)));
         res.send(b"").unwrap();
     }
 
@@ -123,6 +452,33 @@ impl CasClient {
         res.send(b"").unwrap();
     }
 
+    /// Handle a CAS back-channel Single Logout notification.  The CAS server
+    /// POSTs to the registered service url with a `logoutRequest` form field
+    /// holding a SAML `<samlp:LogoutRequest>`; its `<samlp:SessionIndex>` is
+    /// the original service ticket.  This reads the request body, pulls out the
+    /// `logoutRequest` parameter (already percent-decoded by
+    /// `form_urlencoded`), parses the XML, and returns the service ticket so
+    /// the application — or `invalidate_session` — can drop the local session.
+    pub fn parse_logout_request(&self, request: &mut Request) -> Result<Name, VerifyError> {
+        let mut body = String::new();
+        try!(request.read_to_string(&mut body));
+
+        let mut logout_request = None;
+        for (k, v) in form_urlencoded::parse(body.as_bytes()) {
+            if k == "logoutRequest" {
+                logout_request = Some(v);
+            }
+        }
+        let logout_request = try!(logout_request.ok_or(VerifyError::NoTicketFound));
+        parse_session_index(&logout_request)
+    }
+
+    /// Drop the local session a back-channel logout refers to, returning the
+    /// session id that was invalidated (if any) from the `SessionStore`.
+    pub fn invalidate_session(&self, ticket: &str) -> Option<String> {
+        self.session_store.remove(ticket)
+    }
+
     /// When login completes, the CAS server will redirec to your service_url
     /// with the added parameter ticket=\<ticket\>.  You pass \<ticket\> here,
     /// and it checks with the CAS server whether or not the login was
@@ -133,47 +489,165 @@ impl CasClient {
     /// the failure.  In the event of an http error or an xml error, this
     /// returns Err(VerifyError)
     pub fn verify_ticket(&self, ticket: &str) -> Result<ServiceResponse, VerifyError> {
+        self.verify_ticket_with(ticket, None)
+    }
+
+    /// Like `verify_ticket`, but validates against `service` when given,
+    /// instead of the `set_service` override or, failing that, the client's
+    /// own service url.  Pass the same override that
+    /// was used to build the login url (see `LoginOptions::service`); CAS
+    /// rejects the ticket if the two do not match.
+    pub fn verify_ticket_with(&self,
+                              ticket: &str,
+                              service: Option<&str>)
+                              -> Result<ServiceResponse, VerifyError> {
         let mut url: Url = self.verify_url.clone();
-        let param = vec![
-            ("service", self.service_url.serialize()),
+        let service = service.map(|s| s.to_owned())
+            .or_else(|| self.service_override.clone())
+            .unwrap_or_else(|| self.service_url.serialize());
+        let mut param = vec![
+            ("service", service),
             ("ticket", ticket.to_string()),
         ];
+        // Ask for a proxy-granting ticket when a callback is registered.
+        if let Some(ref pgt_url) = self.pgt_callback_url {
+            param.push(("pgtUrl", pgt_url.serialize()));
+        }
         url.set_query_from_pairs(param);
 
-        let res = try!(Client::new().get(&url.serialize()).send());
+        let res = try!(self.fetch(url));
+        self.parse_validation(res)
+    }
+
+    /// GET `url` from the pooled client, following 3xx redirects up to
+    /// `MAX_REDIRECTS` (returning `VerifyError::TooManyRedirects` past the cap)
+    /// and transparently decoding a gzip/deflate body.  Reverse proxies in
+    /// front of the CAS server commonly do both; feeding their output straight
+    /// into `EventReader` would otherwise fail with a confusing
+    /// `VerifyError::Xml`.
+    fn fetch(&self, url: Url) -> Result<Box<Read>, VerifyError> {
+        let mut url = url;
+        let mut hops = 0;
+        loop {
+            let res = try!(self.client.get(&url.serialize()).send());
+            if res.status.is_redirection() {
+                if hops >= MAX_REDIRECTS {
+                    return Err(VerifyError::TooManyRedirects);
+                }
+                let location = match res.headers.get::<Location>() {
+                    Some(loc) => loc.0.clone(),
+                    // A redirect with no Location is nothing we can follow;
+                    // hand the body back and let the parser complain.
+                    None => return CasClient::decode_body(res),
+                };
+                hops += 1;
+                url = try!(redirect_target(&url, &location));
+                continue;
+            }
+            return CasClient::decode_body(res);
+        }
+    }
+
+    /// Wrap a response body in the right decoder for its `Content-Encoding`.
+    fn decode_body(res: ClientResponse) -> Result<Box<Read>, VerifyError> {
+        let encodings = res.headers
+            .get::<ContentEncoding>()
+            .map(|e| e.0.clone())
+            .unwrap_or_default();
+        if encodings.iter().any(|e| *e == Encoding::Gzip) {
+            Ok(Box::new(try!(GzDecoder::new(res))))
+        } else if encodings.iter().any(|e| *e == Encoding::Deflate) {
+            Ok(Box::new(DeflateDecoder::new(res)))
+        } else {
+            Ok(Box::new(res))
+        }
+    }
 
+    /// Parse a CAS `serviceValidate`/`proxyValidate` XML document into a
+    /// `ServiceResponse`.  Shared by `verify_ticket` and `proxy_validate`.
+    fn parse_validation<R: Read>(&self, res: R) -> Result<ServiceResponse, VerifyError> {
         let parser = EventReader::new(res);
-        let mut status = XmlMatchStatus::None;
+
+        // Walk the document keeping an element-name stack so we know our
+        // position in CAS's namespaced schema.  `text` accumulates the
+        // character data of the element currently being closed; it is reset
+        // on every StartElement and flushed on EndElement.
+        let mut stack: Vec<String> = Vec::new();
+        let mut text = String::new();
+        let mut user = String::new();
+        let mut attributes: HashMap<String, Vec<String>> = HashMap::new();
+        let mut pgt_iou: Option<String> = None;
+        let mut proxies: Vec<String> = Vec::new();
+        let mut in_success = false;
+        let mut failure: Option<String> = None;
+
         for e in parser {
             match try!(e) {
-                XmlEvent::StartElement { name, attributes, .. } => {
-                    if name.local_name == "authenticationSuccess" {
-                        status = XmlMatchStatus::ExpectSuccess;
-                    } else if name.local_name == "authenticationFailure" {
-                        let reason = attributes[0].value.clone();
-                        return Ok(ServiceResponse::Failure(reason));
+                XmlEvent::StartElement { name, attributes: attrs, .. } => {
+                    text.clear();
+                    let local = name.local_name;
+                    if local == "authenticationSuccess" {
+                        in_success = true;
+                    } else if local == "authenticationFailure" {
+                        // The reason code lives in the `code` attribute; the
+                        // human-readable reason is the element body (read on
+                        // EndElement below).
+                        failure = Some(attrs.iter()
+                            .find(|a| a.name.local_name == "code")
+                            .map(|a| a.value.clone())
+                            .unwrap_or_default());
                     }
+                    stack.push(local);
                 }
                 XmlEvent::Characters(s) => {
-                    match status {
-                        XmlMatchStatus::None => {}
-                        XmlMatchStatus::ExpectSuccess => {
-                            return Ok(ServiceResponse::Success(s));
+                    text.push_str(&s);
+                }
+                XmlEvent::EndElement { name } => {
+                    let local = name.local_name;
+                    let depth = stack.len();
+                    let body = text.trim().to_owned();
+                    if local == "user" && in_success {
+                        user = body;
+                    } else if local == "proxyGrantingTicket" && in_success {
+                        pgt_iou = Some(body);
+                    } else if local == "proxy" && in_success {
+                        proxies.push(body);
+                    } else if local == "authenticationFailure" {
+                        if !body.is_empty() {
+                            failure = Some(body);
                         }
+                    } else if in_success && depth >= 2 && stack[depth - 2] == "attributes" {
+                        // A direct child of `<cas:attributes>`: its local name
+                        // is the key, its text the value.  Repeated elements
+                        // aggregate into the same Vec.
+                        attributes.entry(local).or_insert_with(Vec::new).push(body);
                     }
+                    text.clear();
+                    stack.pop();
                 }
                 _ => {}
             }
         }
 
+        if let Some(reason) = failure {
+            return Ok(ServiceResponse::Failure(reason));
+        }
+        if !user.is_empty() {
+            return Ok(ServiceResponse::Success(AuthSuccess {
+                user: user,
+                attributes: attributes,
+                pgt_iou: pgt_iou,
+                proxies: proxies,
+            }));
+        }
+
         let error = "did not detect authentication reply from CAS server".to_owned();
         Ok(ServiceResponse::Failure(error))
     }
 
-    /// Takes a reference to a request, and verifies the ticket in that request.
-    /// Will return an `Err(VerifyError::NoTicketFound)` if it can't find the
-    /// ticket in the url query
-    pub fn verify_from_request(&self, request: &Request) -> Result<ServiceResponse, VerifyError> {
+    /// Pull the `ticket` query parameter out of a request, returning
+    /// `Err(VerifyError::NoTicketFound)` if it is absent.
+    pub fn ticket_from_request(&self, request: &Request) -> Result<String, VerifyError> {
         let url = match request.uri.clone() {
             RequestUri::AbsolutePath(s) => try!(Url::parse(&format!("http://none{}", s))),
             RequestUri::AbsoluteUri(u) => u,
@@ -192,7 +666,417 @@ impl CasClient {
         if ticket == "" {
             return Err(VerifyError::NoTicketFound);
         }
+        Ok(ticket)
+    }
+
+    /// Takes a reference to a request, and verifies the ticket in that request.
+    /// Will return an `Err(VerifyError::NoTicketFound)` if it can't find the
+    /// ticket in the url query
+    pub fn verify_from_request(&self, request: &Request) -> Result<ServiceResponse, VerifyError> {
+        let ticket = try!(self.ticket_from_request(request));
+        self.verify_ticket_with(&ticket, self.service_override())
+    }
+
+    /// Handle the proxy-granting-ticket callback.  When CAS validates a ticket
+    /// with a `pgtUrl`, it makes a separate request to that url carrying
+    /// `pgtId` and `pgtIou` query parameters.  Call this from the callback
+    /// handler to record the mapping in the client's `PgtStore`; the `pgtIou`
+    /// can then be matched against the `pgt_iou` in a later `AuthSuccess`.
+    pub fn handle_pgt_callback(&self, request: &Request) -> Result<(), VerifyError> {
+        let url = match request.uri.clone() {
+            RequestUri::AbsolutePath(s) => try!(Url::parse(&format!("http://none{}", s))),
+            RequestUri::AbsoluteUri(u) => u,
+            _ => return Err(VerifyError::UnsupportedUriType),
+        };
+        let queries = try!(url.query_pairs().ok_or(VerifyError::NoTicketFound));
+
+        let mut pgt_id = "".to_owned();
+        let mut pgt_iou = "".to_owned();
+        for (k, v) in queries {
+            if k == "pgtId" {
+                pgt_id = v;
+            } else if k == "pgtIou" {
+                pgt_iou = v;
+            }
+        }
+        if pgt_id == "" || pgt_iou == "" {
+            return Err(VerifyError::NoTicketFound);
+        }
+        self.pgt_store.store(&pgt_iou, &pgt_id);
+        Ok(())
+    }
+
+    /// Trade a proxy-granting ticket for a proxy ticket good against
+    /// `target_service`, by calling the CAS `/proxy` endpoint.  Returns the
+    /// `<cas:proxyTicket>` on success.
+    pub fn get_proxy_ticket(&self,
+                            pgt: &str,
+                            target_service: &str)
+                            -> Result<String, VerifyError> {
+        let proxy_url = match self.proxy_url {
+            Some(ref u) => u.clone(),
+            None => return Err(VerifyError::ProxyNotConfigured),
+        };
+
+        let mut url = proxy_url;
+        let param = vec![
+            ("pgt", pgt.to_string()),
+            ("targetService", target_service.to_string()),
+        ];
+        url.set_query_from_pairs(param);
+
+        let res = try!(self.fetch(url));
+
+        // `/proxy` returns `<cas:proxySuccess><cas:proxyTicket>PT-..` or a
+        // `<cas:proxyFailure code=..>reason`.
+        let parser = EventReader::new(res);
+        let mut text = String::new();
+        let mut ticket: Option<String> = None;
+        let mut failure: Option<String> = None;
+        for e in parser {
+            match try!(e) {
+                XmlEvent::StartElement { name, attributes: attrs, .. } => {
+                    text.clear();
+                    if name.local_name == "proxyFailure" {
+                        failure = Some(attrs.iter()
+                            .find(|a| a.name.local_name == "code")
+                            .map(|a| a.value.clone())
+                            .unwrap_or_default());
+                    }
+                }
+                XmlEvent::Characters(s) => text.push_str(&s),
+                XmlEvent::EndElement { name } => {
+                    let local = name.local_name;
+                    let body = text.trim().to_owned();
+                    if local == "proxyTicket" {
+                        ticket = Some(body);
+                    } else if local == "proxyFailure" && !body.is_empty() {
+                        failure = Some(body);
+                    }
+                    text.clear();
+                }
+                _ => {}
+            }
+        }
+
+        match ticket {
+            Some(t) => Ok(t),
+            None => Err(VerifyError::NoProxyTicket(failure.unwrap_or_else(|| {
+                "did not detect proxy ticket reply from CAS server".to_owned()
+            }))),
+        }
+    }
+
+    /// Validate a proxy ticket against the CAS `proxyValidate` endpoint.  On
+    /// success the returned `AuthSuccess` carries the username and the
+    /// `<cas:proxies>` chain describing how the ticket was proxied.
+    pub fn proxy_validate(&self, ticket: &str) -> Result<ServiceResponse, VerifyError> {
+        let proxy_validate_url = match self.proxy_validate_url {
+            Some(ref u) => u.clone(),
+            None => return Err(VerifyError::ProxyNotConfigured),
+        };
+
+        let mut url = proxy_validate_url;
+        let mut param = vec![
+            ("service", self.service_override.clone()
+                .unwrap_or_else(|| self.service_url.serialize())),
+            ("ticket", ticket.to_string()),
+        ];
+        if let Some(ref pgt_url) = self.pgt_callback_url {
+            param.push(("pgtUrl", pgt_url.serialize()));
+        }
+        url.set_query_from_pairs(param);
+
+        let res = try!(self.fetch(url));
+        self.parse_validation(res)
+    }
+}
+
+/// A builder for `CasClient`, handy when you want to supply a custom
+/// `hyper::Client` (for a pinned or internal CA) without spelling out the long
+/// `with_client` argument list.  Call `client` to override the default pooled
+/// client, then `build`.
+pub struct CasClientBuilder {
+    base_url: String,
+    login_path: String,
+    logout_path: String,
+    verify_path: String,
+    service_url: String,
+    client: Option<Client>,
+}
+
+impl CasClientBuilder {
+    /// Start a builder with the same urls `CasClient::new` takes
+    pub fn new(base_url: &str,
+               login_path: &str,
+               logout_path: &str,
+               verify_path: &str,
+               service_url: &str)
+               -> CasClientBuilder {
+        CasClientBuilder {
+            base_url: base_url.to_owned(),
+            login_path: login_path.to_owned(),
+            logout_path: logout_path.to_owned(),
+            verify_path: verify_path.to_owned(),
+            service_url: service_url.to_owned(),
+            client: None,
+        }
+    }
+
+    /// Use a pre-configured `hyper::Client` (e.g. one with a custom TLS
+    /// connector) instead of the default `Client::new()`
+    pub fn client(mut self, client: Client) -> CasClientBuilder {
+        self.client = Some(client);
+        self
+    }
+
+    /// Build the `CasClient`
+    pub fn build(self) -> Result<CasClient, ParseError> {
+        match self.client {
+            Some(client) => {
+                CasClient::with_client(&self.base_url,
+                                       &self.login_path,
+                                       &self.logout_path,
+                                       &self.verify_path,
+                                       &self.service_url,
+                                       client)
+            }
+            None => {
+                CasClient::new(&self.base_url,
+                               &self.login_path,
+                               &self.logout_path,
+                               &self.verify_path,
+                               &self.service_url)
+            }
+        }
+    }
+}
+
+/// An application handler that only runs once CAS authentication has
+/// succeeded.  It receives the resolved `AuthSuccess` alongside the usual
+/// hyper request/response so protected routes can read the username and any
+/// released attributes without repeating the login dance.
+pub trait AuthenticatedHandler: Send + Sync {
+    /// Serve an authenticated request
+    fn handle(&self, auth: &AuthSuccess, req: Request, res: Response);
+}
+
+/// A hyper `Handler` that gates an inner `AuthenticatedHandler` behind CAS.
+/// On each request it first honours an existing session cookie; failing that
+/// it consumes a `ticket` query parameter (the CAS redirect-back), validates
+/// it, and establishes a session; and with neither present it issues the 302
+/// login redirect automatically.  This is the centralized-auth-gateway pattern
+/// adapted to this crate's CAS flow, giving applications drop-in protected
+/// routes instead of hand-wiring `login_redirect`/`verify_ticket`.
+pub struct CasGuard<H: AuthenticatedHandler> {
+    cas_client: CasClient,
+    inner: H,
+    /// The guard's own session-id -> username map, consulted on the cookie
+    /// path.  This is kept separate from `CasClient.session_store`, which holds
+    /// the service-ticket -> session-id mapping Single Logout needs, so the two
+    /// schemas don't collide.
+    sessions: Mutex<HashMap<String, String>>,
+}
+
+impl<H: AuthenticatedHandler> CasGuard<H> {
+    /// Wrap `inner` so it only runs for CAS-authenticated requests
+    pub fn new(cas_client: CasClient, inner: H) -> CasGuard<H> {
+        CasGuard {
+            cas_client: cas_client,
+            inner: inner,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Drop the session a back-channel `logoutRequest` refers to.  The SAML
+    /// `SessionIndex` is the original service ticket; this resolves it to the
+    /// guard session id via the `SessionStore` and forgets both.
+    pub fn logout_session(&self, ticket: &str) {
+        if let Some(session_id) = self.cas_client.invalidate_session(ticket) {
+            self.sessions.lock().unwrap().remove(&session_id);
+        }
+    }
+
+    /// Read the value of our session cookie from the request, if present
+    fn session_cookie(request: &Request) -> Option<String> {
+        let raw = match request.headers.get_raw("Cookie") {
+            Some(raw) => raw,
+            None => return None,
+        };
+        for line in raw {
+            if let Ok(header) = str::from_utf8(line) {
+                for pair in header.split(';') {
+                    let mut kv = pair.trim().splitn(2, '=');
+                    if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+                        if k == CAS_SESSION_COOKIE {
+                            return Some(v.to_owned());
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<H: AuthenticatedHandler> Handler for CasGuard<H> {
+    fn handle(&self, request: Request, mut response: Response) {
+        // An established session cookie short-circuits validation.  The guard
+        // map only keeps the username, so attributes are empty on this path.
+        if let Some(session) = CasGuard::<H>::session_cookie(&request) {
+            let user = self.sessions.lock().unwrap().get(&session).cloned();
+            if let Some(user) = user {
+                let auth = AuthSuccess {
+                    user: user,
+                    attributes: HashMap::new(),
+                    pgt_iou: None,
+                    proxies: Vec::new(),
+                };
+                self.inner.handle(&auth, request, response);
+                return;
+            }
+        }
+
+        // No session: if the CAS server just redirected back with a ticket,
+        // validate it and establish a session.
+        match self.cas_client.ticket_from_request(&request) {
+            Ok(ticket) => {
+                let service = self.cas_client.service_override();
+                match self.cas_client.verify_ticket_with(&ticket, service) {
+                    Ok(ServiceResponse::Success(auth)) => {
+                        // The service ticket is single-use and travels in the
+                        // redirect URL, so never reuse it as the session
+                        // secret — mint a fresh opaque id instead.
+                        let session_id = new_session_id();
+                        // Guard map: session-id -> username (cookie lookups).
+                        self.sessions.lock().unwrap()
+                            .insert(session_id.clone(), auth.user.clone());
+                        // SessionStore: service-ticket -> session-id, so a
+                        // back-channel logout (keyed on the ticket) can find
+                        // and drop this session via `logout_session`.
+                        self.cas_client.session_store.store(&ticket, &session_id);
+                        let cookie = format!("{}={}; Path=/; HttpOnly; Secure; SameSite=Lax",
+                                             CAS_SESSION_COOKIE,
+                                             session_id);
+                        response.headers_mut()
+                            .set_raw("Set-Cookie", vec![cookie.into_bytes()]);
+                        self.inner.handle(&auth, request, response);
+                    }
+                    // A ticket the CAS server rejected just means re-login.
+                    Ok(ServiceResponse::Failure(_)) => {
+                        self.cas_client.login_redirect(response)
+                    }
+                    // A transport error (CAS unreachable) must NOT redirect:
+                    // login would bounce back with another ticket that again
+                    // fails, looping forever.  Surface it as a 502 instead.
+                    Err(_) => {
+                        *response.status_mut() = StatusCode::BadGateway;
+                        response.send(b"").unwrap();
+                    }
+                }
+            }
+            // Neither session nor ticket: bounce the browser to CAS login.
+            Err(_) => self.cas_client.login_redirect(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::url::Url;
+
+    fn client() -> CasClient {
+        CasClient::new("https://cas.example.edu/cas/",
+                       "login",
+                       "logout",
+                       "serviceValidate",
+                       "https://svc.example.edu/")
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_user_and_repeated_attributes() {
+        let xml = "<cas:serviceResponse xmlns:cas='http://www.yale.edu/tp/cas'>\
+                     <cas:authenticationSuccess>\
+                       <cas:user>jdoe</cas:user>\
+                       <cas:attributes>\
+                         <cas:email>jdoe@example.edu</cas:email>\
+                         <cas:memberOf>staff</cas:memberOf>\
+                         <cas:memberOf>admins</cas:memberOf>\
+                       </cas:attributes>\
+                     </cas:authenticationSuccess>\
+                   </cas:serviceResponse>";
+        match client().parse_validation(xml.as_bytes()).unwrap() {
+            ServiceResponse::Success(auth) => {
+                assert_eq!(auth.user, "jdoe");
+                assert_eq!(auth.attributes["email"], vec!["jdoe@example.edu"]);
+                // A repeated element aggregates into the same Vec, in order.
+                assert_eq!(auth.attributes["memberOf"], vec!["staff", "admins"]);
+            }
+            other => panic!("expected success, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn authentication_failure_reports_the_body_reason() {
+        let xml = "<cas:serviceResponse xmlns:cas='http://www.yale.edu/tp/cas'>\
+                     <cas:authenticationFailure code='INVALID_TICKET'>\
+                       ticket ST-123 not recognized\
+                     </cas:authenticationFailure>\
+                   </cas:serviceResponse>";
+        match client().parse_validation(xml.as_bytes()).unwrap() {
+            ServiceResponse::Failure(reason) => {
+                assert_eq!(reason, "ticket ST-123 not recognized");
+            }
+            other => panic!("expected failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_authentication_failure_falls_back_to_the_code() {
+        let xml = "<cas:serviceResponse xmlns:cas='http://www.yale.edu/tp/cas'>\
+                     <cas:authenticationFailure code='INVALID_TICKET'></cas:authenticationFailure>\
+                   </cas:serviceResponse>";
+        match client().parse_validation(xml.as_bytes()).unwrap() {
+            ServiceResponse::Failure(reason) => assert_eq!(reason, "INVALID_TICKET"),
+            other => panic!("expected failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn session_index_is_extracted_from_a_logout_request() {
+        let xml = "<samlp:LogoutRequest xmlns:samlp='urn:oasis:names:tc:SAML:2.0:protocol'>\
+                     <saml:NameID xmlns:saml='urn:oasis:names:tc:SAML:2.0:assertion'>\
+                       @NOT_USED@\
+                     </saml:NameID>\
+                     <samlp:SessionIndex>ST-9876-abcdef</samlp:SessionIndex>\
+                   </samlp:LogoutRequest>";
+        assert_eq!(parse_session_index(xml).unwrap(), "ST-9876-abcdef");
+    }
+
+    #[test]
+    fn missing_session_index_is_an_error() {
+        let xml = "<samlp:LogoutRequest xmlns:samlp='urn:oasis:names:tc:SAML:2.0:protocol'>\
+                   </samlp:LogoutRequest>";
+        match parse_session_index(xml) {
+            Err(VerifyError::NoSessionIndex) => {}
+            other => panic!("expected NoSessionIndex, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn same_host_redirect_keeps_the_query() {
+        let base = Url::parse("https://cas.example.edu/cas/serviceValidate?ticket=ST-1").unwrap();
+        let next = redirect_target(&base, "https://cas.example.edu/cas/other?ticket=ST-1").unwrap();
+        assert!(next.serialize().contains("ticket=ST-1"));
+    }
 
-        self.verify_ticket(&ticket)
+    #[test]
+    fn cross_host_redirect_strips_the_query() {
+        let base = Url::parse("https://cas.example.edu/cas/serviceValidate?ticket=ST-1").unwrap();
+        let next = redirect_target(&base, "https://other.example.org/landing?ticket=ST-1").unwrap();
+        assert!(!next.serialize().contains("ticket"));
+        assert_eq!(next.domain(), Some("other.example.org"));
     }
 }